@@ -0,0 +1,40 @@
+use axum::extract::Extension;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hyper::Body;
+
+use crate::state::State;
+
+pub(crate) const API_KEY_HEADER: &str = "x-api-key";
+
+// Checks the `x-api-key` header against `state`: `Ok(())` when auth is
+// disabled or the presented key matches a configured key, `Err(401)` when
+// the header is absent, `Err(403)` on a mismatch. Shared by the `base`
+// router middleware and any other handler (e.g. `/metrics`) that needs the
+// same check without running the whole request through a middleware layer.
+pub(crate) fn authorize(state: &State, headers: &HeaderMap) -> Result<(), StatusCode> {
+    if state.auth_disabled() {
+        return Ok(());
+    }
+
+    match headers.get(API_KEY_HEADER).map(|v| v.to_str()) {
+        None => Err(StatusCode::UNAUTHORIZED),
+        Some(Ok(value)) if state.check_api_key(value) => Ok(()),
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+// Guards the authenticated management routes with the `x-api-key` header:
+// 401 when it's absent, 403 when it doesn't match a configured key. Left
+// open when no `--auth-key`s were configured at all.
+pub async fn require_api_key(
+    Extension(state): Extension<State>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    match authorize(&state, req.headers()) {
+        Ok(()) => next.run(req).await,
+        Err(status) => status.into_response(),
+    }
+}
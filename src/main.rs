@@ -1,5 +1,7 @@
 use axum::{
     handler::Handler,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
     routing::{get},
     Router,
     middleware,
@@ -11,16 +13,20 @@ use env_logger::{Builder, Target};
 use log::LevelFilter;
 use std::io::Write;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tower_http::trace::TraceLayer;
 
+mod auth;
 mod error;
 mod handlers;
 mod https;
 mod metrics;
+mod sse;
 mod state;
+mod websocket;
 
 use crate::metrics::{setup_metrics_recorder, track_metrics};
-use handlers::{metrics, handler_404, health, root};
+use handlers::{handler_404, health, root};
 use state::State;
 
 #[tokio::main]
@@ -47,6 +53,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .env("RUST_API_TIMEOUT")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("refresh-interval")
+                .long("refresh-interval")
+                .help("Set how often to refresh cached data from Kraken, in seconds")
+                .default_value("60")
+                .env("RUST_API_REFRESH_INTERVAL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("api-key")
+                .long("api-key")
+                .help("Kraken API key, enables authenticated private endpoints")
+                .env("RUST_API_KRAKEN_KEY")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("api-secret")
+                .long("api-secret")
+                .help("Kraken API secret, enables authenticated private endpoints")
+                .env("RUST_API_KRAKEN_SECRET")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("auth-key")
+                .long("auth-key")
+                .help("API key required to access the authenticated management routes; repeatable, leave unset to disable")
+                .env("RUST_API_AUTH_KEYS")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("reference-currencies")
+                .long("reference-currencies")
+                .help("Allow-list of reference currencies to pair every asset against, e.g. USD,EUR (default: built-in list)")
+                .env("RUST_API_REFERENCE_CURRENCIES")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("pairs")
+                .long("pairs")
+                .help("Explicit allow-list of pairs to export by wsname, e.g. XBT/USD,ETH/EUR; skips reference-currency permutation when set")
+                .env("RUST_API_PAIRS")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true),
+        )
         .get_matches();
 
     // Initialize log Builder
@@ -71,20 +126,88 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         8080
     });
 
+    // Set refresh interval
+    let refresh_interval: u64 = opts
+        .value_of("refresh-interval")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("Supplied refresh-interval not in range, defaulting to 60");
+            60
+        });
+
     // Create state for axum
     let state = State::new(opts.clone()).await?;
 
     // Create prometheus handle
     let recorder_handle = setup_metrics_recorder();
 
-    // These should be authenticated
+    // Keep the exchange rate gauges continuously up to date via Kraken's
+    // websocket feed, independent of any REST scrape.
+    let ws_state = state.clone();
+    tokio::spawn(async move {
+        websocket::run(ws_state).await;
+    });
+
+    // Decouple `/metrics` scrapes from Kraken: refresh the gauges and the
+    // rendered Prometheus snapshot on a timer rather than on every scrape.
+    let refresh_state = state.clone();
+    let refresh_recorder = recorder_handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(refresh_interval));
+        loop {
+            interval.tick().await;
+            match refresh_state.generate().await {
+                Ok(()) => {
+                    if let Err(e) = refresh_state.generate_private().await {
+                        log::error!("failed to refresh private account data from kraken: {}", e);
+                        refresh_state.record_scrape_error();
+                    }
+                    refresh_state
+                        .set_cached_metrics(refresh_recorder.render())
+                        .await;
+                }
+                Err(e) => {
+                    log::error!("failed to refresh data from kraken: {}", e);
+                    refresh_state.record_scrape_error();
+                }
+            }
+        }
+    });
+
+    // Authenticated management surface, guarded by `--auth-key`.
     let base = Router::new()
-        .route("/", get(root));
+        .route("/", get(root))
+        .route_layer(middleware::from_fn(auth::require_api_key));
 
     // These should NOT be authenticated
     let standard = Router::new()
         .route("/health", get(health))
-        .route("/metrics", get(metrics));
+        .route("/metrics", get(metrics))
+        .route("/stream", get(sse::stream));
+
+    // Serve the snapshot the refresh task last rendered, rather than calling
+    // out to Kraken on every scrape. `/metrics` itself stays unauthenticated
+    // UNLESS Kraken credentials are configured: in that case the snapshot
+    // also carries `kraken_account_balance`/`kraken_open_orders`, so the same
+    // `--auth-key` check `base` uses is enforced here too, rather than
+    // leaving account balances readable by anyone who can reach the route.
+    async fn metrics(Extension(state): Extension<State>, headers: HeaderMap) -> Response {
+        if state.has_credentials() {
+            if state.auth_disabled() {
+                log::warn!(
+                    "kraken API credentials configured without --auth-key; refusing /metrics to avoid leaking balances"
+                );
+                return axum::http::StatusCode::UNAUTHORIZED.into_response();
+            }
+
+            if let Err(status) = auth::authorize(&state, &headers) {
+                return status.into_response();
+            }
+        }
+
+        state.cached_metrics().await.into_response()
+    }
 
     let app = Router::new()
         .merge(base)
@@ -0,0 +1,31 @@
+use axum::extract::Extension;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::Stream;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::state::State;
+
+// Streams exchange-rate updates to clients as they're published, so
+// dashboards can react live instead of polling `/metrics`.
+pub async fn stream(
+    Extension(state): Extension<State>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(state.subscribe()).filter_map(|message| match message {
+        Ok(event) => match serde_json::to_string(&event) {
+            Ok(json) => Some(Ok(Event::default().data(json))),
+            Err(e) => {
+                log::warn!("failed to serialize ticker event: {}", e);
+                None
+            }
+        },
+        Err(_) => {
+            log::warn!("stream client lagged behind and missed ticker events");
+            None
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
@@ -1,24 +1,88 @@
+use base64::engine::general_purpose::STANDARD as base64;
+use base64::Engine as _;
+use chrono::Utc;
 use clap::ArgMatches;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
 use std::error::Error;
 use hyper::{Body, Request};
 use std::collections::HashMap;
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 use hyper::body::Bytes;
-use metrics::gauge;
+use metrics::{counter, gauge};
+use tokio::sync::{broadcast, RwLock};
 
 use crate::https::{HttpsClient, ClientBuilder};
 use crate::error::Error as RestError;
 
-type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+pub(crate) type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 
 const ASSET_PAIRS: &str = "https://api.kraken.com/0/public/AssetPairs";
 const ASSETS: &str = "https://api.kraken.com/0/public/Assets";
 const TICKER: &str = "https://api.kraken.com/0/public/Ticker";
 const REFERENCE_CURRENCIES: &'static [&'static str] = &["AUD", "CAD", "BTC", "ETH", "EUR", "GBP", "JPY", "USD", "XBT", "USDT", "USDC"];
 
+const KRAKEN_API_BASE: &str = "https://api.kraken.com";
+const BALANCE_PATH: &str = "/0/private/Balance";
+const OPEN_ORDERS_PATH: &str = "/0/private/OpenOrders";
+
+// Capacity of the broadcast channel backing `/stream`. Slow SSE clients that
+// fall this far behind the latest tick simply miss the oldest events rather
+// than block publishers.
+const TICKER_EVENT_CHANNEL_CAPACITY: usize = 256;
+
 #[derive(Clone, Debug)]
 pub struct State {
     pub client: HttpsClient,
+    ticker_tx: broadcast::Sender<TickerEvent>,
+    // Last-rendered `/metrics` body, so scrapes are served instantly from
+    // cache instead of round-tripping to Kraken.
+    metrics_cache: Arc<RwLock<String>>,
+    // Only present when `--api-key`/`--api-secret` were supplied; gates all
+    // authenticated private-endpoint requests.
+    credentials: Option<Credentials>,
+    // Salted SHA-256 digests of the configured `--auth-key` values, never
+    // the plaintext keys themselves. Empty when no auth keys were supplied,
+    // in which case `base` is left unprotected.
+    auth_keys: Arc<Vec<[u8; 32]>>,
+    auth_salt: Arc<[u8; 16]>,
+    // Allow-list of reference currencies to permute every asset against.
+    // Defaults to `REFERENCE_CURRENCIES` when `--reference-currencies` isn't set.
+    reference_currencies: Vec<String>,
+    // When set via `--pairs`, an explicit allow-list of `wsname`s (e.g.
+    // "XBT/USD") to export; skips reference-currency permutation entirely.
+    explicit_pairs: Option<Vec<String>>,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    api_key: String,
+    api_secret: String,
+}
+
+// Manual Debug impl so `State`'s derive never prints the secret.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("api_key", &"***")
+            .field("api_secret", &"***")
+            .finish()
+    }
+}
+
+// A single exchange-rate update, published to `/stream` subscribers whenever
+// a ticker value is written, whether from the REST `generate` path or the
+// websocket task.
+#[derive(Clone, Debug, Serialize)]
+pub struct TickerEvent {
+    pub pair: String,
+    pub currency: String,
+    pub reference_currency: String,
+    pub rate: f64,
+    pub volume: f64,
+    pub timestamp: i64,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -38,12 +102,12 @@ pub struct Asset {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AssetPairs {
   error: Vec<String>,
-  result: HashMap<String, AssetPair>
+  pub(crate) result: HashMap<String, AssetPair>
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AssetPair {
-  wsname: String,
+  pub(crate) wsname: String,
   base: String,
   quote: String
 }
@@ -56,10 +120,27 @@ pub struct Tickers {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Info {
-  c: Vec<String>,
-  v: Vec<String>,
-  p: Vec<String>,
-  t: Vec<u32>
+  pub(crate) c: Vec<String>,
+  pub(crate) v: Vec<String>,
+  pub(crate) p: Vec<String>,
+  pub(crate) t: Vec<u32>
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Balance {
+  error: Vec<String>,
+  result: HashMap<String, String>
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OpenOrders {
+  error: Vec<String>,
+  result: OpenOrdersResult
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OpenOrdersResult {
+  open: HashMap<String, serde_json::Value>
 }
 
 impl State {
@@ -75,20 +156,158 @@ impl State {
             });
 
         let client = ClientBuilder::new().timeout(timeout).build()?;
+        let (ticker_tx, _) = broadcast::channel(TICKER_EVENT_CHANNEL_CAPACITY);
+
+        let credentials = match (opts.value_of("api-key"), opts.value_of("api-secret")) {
+            (Some(api_key), Some(api_secret)) => {
+                log::info!("kraken API credentials supplied, enabling private endpoints");
+                Some(Credentials {
+                    api_key: api_key.to_string(),
+                    api_secret: api_secret.to_string(),
+                })
+            }
+            (None, None) => None,
+            _ => {
+                eprintln!("both --api-key and --api-secret are required to enable private endpoints, ignoring");
+                None
+            }
+        };
+
+        let mut auth_salt = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut auth_salt);
+
+        let auth_keys: Vec<[u8; 32]> = match opts.values_of("auth-key") {
+            Some(values) => {
+                log::info!("auth keys supplied, protecting the authenticated management routes");
+                values.map(|key| hash_auth_key(&auth_salt, key)).collect()
+            }
+            None => Vec::new(),
+        };
+
+        let reference_currencies: Vec<String> = match opts.values_of("reference-currencies") {
+            Some(values) => values.map(|v| v.to_string()).collect(),
+            None => REFERENCE_CURRENCIES.iter().map(|c| c.to_string()).collect(),
+        };
+
+        let explicit_pairs: Option<Vec<String>> = opts
+            .values_of("pairs")
+            .map(|values| values.map(|v| v.to_string()).collect());
+
+        if let Some(pairs) = &explicit_pairs {
+            log::info!("explicit pair allow-list supplied, skipping permutation: {:?}", pairs);
+        }
 
         Ok(State {
             client,
+            ticker_tx,
+            metrics_cache: Arc::new(RwLock::new(String::new())),
+            credentials,
+            auth_keys: Arc::new(auth_keys),
+            auth_salt: Arc::new(auth_salt),
+            reference_currencies,
+            explicit_pairs,
         })
     }
 
-    pub async fn get(&self, url: &str) -> Result<Bytes, RestError> {
+    // True when no auth keys were configured, so the authenticated routes
+    // are left open.
+    pub(crate) fn auth_disabled(&self) -> bool {
+        self.auth_keys.is_empty()
+    }
+
+    // True when `--api-key`/`--api-secret` are set, i.e. `/metrics` carries
+    // private account gauges and must not be served unauthenticated.
+    pub(crate) fn has_credentials(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    // Constant-time check of a presented API key against every configured
+    // key's salted digest, so a mismatch can't leak which key (if any) it
+    // was closest to.
+    pub(crate) fn check_api_key(&self, presented: &str) -> bool {
+        let hashed = hash_auth_key(&self.auth_salt, presented);
+        self.auth_keys
+            .iter()
+            .fold(false, |matched, key| matched | constant_time_eq(key, &hashed))
+    }
+
+    // Subscribe to exchange-rate updates for the `/stream` SSE endpoint.
+    pub fn subscribe(&self) -> broadcast::Receiver<TickerEvent> {
+        self.ticker_tx.subscribe()
+    }
 
+    // Publish a ticker update. Errors only when there are no subscribers and
+    // are not worth logging, so they're dropped.
+    pub(crate) fn publish(&self, event: TickerEvent) {
+        let _ = self.ticker_tx.send(event);
+    }
+
+    // Last-rendered Prometheus text, served directly by the `/metrics` handler.
+    pub async fn cached_metrics(&self) -> String {
+        self.metrics_cache.read().await.clone()
+    }
+
+    pub(crate) async fn set_cached_metrics(&self, rendered: String) {
+        *self.metrics_cache.write().await = rendered;
+
+        gauge!("kraken_last_scrape_success_timestamp", Utc::now().timestamp() as f64);
+    }
+
+    pub(crate) fn record_scrape_error(&self) {
+        counter!("kraken_scrape_errors_total", 1);
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Bytes, RestError> {
         let req = Request::builder()
             .method("GET")
             .uri(url)
             .body(Body::empty())
             .expect("request builder");
 
+        self.send(req).await
+    }
+
+    // Sign and POST to a Kraken private endpoint. `params` are additional
+    // form fields beyond the nonce Kraken requires on every private call.
+    async fn post_private(&self, path: &str, params: &[(&str, &str)]) -> Result<Bytes, RestError> {
+        let credentials = self.credentials.as_ref().ok_or(RestError::Unauthorized)?;
+
+        let nonce = Utc::now().timestamp_millis().to_string();
+
+        let mut body = format!("nonce={}", nonce);
+        for (key, value) in params {
+            body.push('&');
+            body.push_str(key);
+            body.push('=');
+            body.push_str(&url_encode(value));
+        }
+
+        let mut sha256 = Sha256::new();
+        sha256.update(nonce.as_bytes());
+        sha256.update(body.as_bytes());
+
+        let secret = base64
+            .decode(&credentials.api_secret)
+            .map_err(|_| RestError::Unauthorized)?;
+        let mut mac =
+            Hmac::<Sha512>::new_from_slice(&secret).map_err(|_| RestError::Unauthorized)?;
+        mac.update(path.as_bytes());
+        mac.update(&sha256.finalize());
+        let signature = base64.encode(mac.finalize().into_bytes());
+
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("{}{}", KRAKEN_API_BASE, path))
+            .header("API-Key", &credentials.api_key)
+            .header("API-Sign", signature)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .expect("request builder");
+
+        self.send(req).await
+    }
+
+    async fn send(&self, req: Request<Body>) -> Result<Bytes, RestError> {
         let response = self.client.clone().request(req).await?;
 
         match response.status().as_u16() {
@@ -108,11 +327,32 @@ impl State {
         }
     }
 
-    pub async fn generate(&self) -> Result<(), RestError> {
+    // Discover which Kraken pair codes actually exist by permuting every known
+    // asset against every configured reference currency, or, when `--pairs` is
+    // set, by matching those `wsname`s directly. Shared by the REST `generate` path
+    // and the websocket subscription setup so both agree on the same pair set.
+    async fn discover(&self) -> Result<(AssetPairs, Vec<String>), RestError> {
         let bytes = self.get(ASSET_PAIRS).await?;
         let asset_pairs: AssetPairs = serde_json::from_slice(&bytes)?;
         log::debug!("{:?}", asset_pairs);
 
+        // An explicit `--pairs` allow-list looks pairs up directly by
+        // `wsname` instead of guessing at Kraken's pair-naming permutations.
+        if let Some(pairs) = &self.explicit_pairs {
+            let vec: Vec<String> = asset_pairs
+                .result
+                .iter()
+                .filter(|(_, pair)| pairs.contains(&pair.wsname))
+                .map(|(code, _)| code.clone())
+                .collect();
+
+            if vec.is_empty() {
+                log::warn!("no pairs from --pairs matched kraken's asset pairs: {:?}", pairs);
+            }
+
+            return Ok((asset_pairs, vec));
+        }
+
         let bytes = self.get(ASSETS).await?;
         let assets: Assets = serde_json::from_slice(&bytes)?;
         log::debug!("{:?}", assets);
@@ -120,7 +360,7 @@ impl State {
         let mut vec: Vec<String> = Vec::new();
         for (_, asset) in assets.result.iter() {
             log::debug!("Looping over {}", asset.altname);
-            for reference_currency in REFERENCE_CURRENCIES {
+            for reference_currency in self.reference_currencies.iter() {
                 let pair = format!("{}{}", asset.altname, reference_currency);
                 log::trace!("Checking if {} exists", pair);
                 if asset_pairs.result.contains_key(&pair) {
@@ -144,6 +384,64 @@ impl State {
             }
         }
 
+        Ok((asset_pairs, vec))
+    }
+
+    // Fetch account balances and open orders from Kraken's private endpoints
+    // and publish them as gauges. A no-op when no credentials were supplied,
+    // so callers can include it unconditionally alongside `generate`.
+    pub async fn generate_private(&self) -> Result<(), RestError> {
+        if self.credentials.is_none() {
+            return Ok(());
+        }
+
+        let bytes = self.post_private(BALANCE_PATH, &[]).await?;
+        let balance: Balance = serde_json::from_slice(&bytes)?;
+        if !balance.error.is_empty() {
+            log::error!("kraken balance request returned errors: {:?}", balance.error);
+            return Err(RestError::Unknown);
+        }
+        for (asset, amount) in balance.result.iter() {
+            let value = match amount.parse::<f64>() {
+                Ok(value) => value,
+                Err(e) => {
+                    log::warn!("unparseable kraken balance for {}: {:?}: {}", asset, amount, e);
+                    continue;
+                }
+            };
+            let labels = [("asset", asset.to_string())];
+            gauge!("kraken_account_balance", value, &labels);
+        }
+
+        let bytes = self.post_private(OPEN_ORDERS_PATH, &[]).await?;
+        let open_orders: OpenOrders = serde_json::from_slice(&bytes)?;
+        if !open_orders.error.is_empty() {
+            log::error!("kraken open orders request returned errors: {:?}", open_orders.error);
+            return Err(RestError::Unknown);
+        }
+        gauge!("kraken_open_orders", open_orders.result.open.len() as f64);
+
+        Ok(())
+    }
+
+    // Resolve the discovered pair codes down to the `wsname`s Kraken's
+    // websocket feed expects in a `subscribe` request, e.g. "XBT/USD".
+    pub(crate) async fn discover_wsnames(&self) -> Result<Vec<String>, RestError> {
+        let (asset_pairs, pair_codes) = self.discover().await?;
+
+        let mut wsnames: Vec<String> = pair_codes
+            .iter()
+            .filter_map(|code| asset_pairs.result.get(code).map(|pair| pair.wsname.clone()))
+            .collect();
+        wsnames.sort();
+        wsnames.dedup();
+
+        Ok(wsnames)
+    }
+
+    pub async fn generate(&self) -> Result<(), RestError> {
+        let (asset_pairs, vec) = self.discover().await?;
+
         let assets_query = vec.join(",");
         log::debug!("{:#?}", assets_query);
 
@@ -153,22 +451,84 @@ impl State {
         let tickers: Tickers = serde_json::from_slice(&bytes)?;
 
         for (asset, value) in tickers.result.iter() {
-            let asset_pair = &asset_pairs.result.get(asset).unwrap();
+            let Some(asset_pair) = asset_pairs.result.get(asset) else {
+                log::warn!("kraken ticker response referenced unknown asset pair: {}", asset);
+                continue;
+            };
 //            let wsname = asset_pair.wsname.to_string();
             let wsname_split: Vec<&str> = asset_pair.wsname.split('/').collect();
+            if wsname_split.len() != 2 {
+                log::warn!("unexpected pair name from kraken asset pairs: {}", asset_pair.wsname);
+                continue;
+            }
+
+            let (Some(rate), Some(volume), Some(avg), Some(avg_last_day), Some(trades)) = (
+                value.c.get(0).and_then(|v| v.parse::<f64>().ok()),
+                value.v.get(1).and_then(|v| v.parse::<f64>().ok()),
+                value.p.get(0).and_then(|v| v.parse::<f64>().ok()),
+                value.p.get(1).and_then(|v| v.parse::<f64>().ok()),
+                value.t.get(1).copied(),
+            ) else {
+                log::warn!("malformed kraken ticker payload for {}: {:?}", asset_pair.wsname, value);
+                continue;
+            };
 
             let labels = [
                 ("currency", wsname_split[0].to_string()),
                 ("reference_currency", wsname_split[1].to_string()),
                 ("pair", asset_pair.wsname.to_string())
             ];
-            gauge!("exchange_rate", value.c[0].parse::<f64>().unwrap(), &labels);
-            gauge!("exchange_volume_daily", value.v[1].parse::<f64>().unwrap(), &labels);
-            gauge!("exchange_rate_average", value.p[0].parse::<f64>().unwrap(), &labels);
-            gauge!("exchange_rate_average_last_day", value.p[1].parse::<f64>().unwrap(), &labels);
-            gauge!("exchange_trades_daily", value.t[1] as f64, &labels);
+            gauge!("exchange_rate", rate, &labels);
+            gauge!("exchange_volume_daily", volume, &labels);
+            gauge!("exchange_rate_average", avg, &labels);
+            gauge!("exchange_rate_average_last_day", avg_last_day, &labels);
+            gauge!("exchange_trades_daily", trades as f64, &labels);
+
+            self.publish(TickerEvent {
+                pair: asset_pair.wsname.to_string(),
+                currency: wsname_split[0].to_string(),
+                reference_currency: wsname_split[1].to_string(),
+                rate,
+                volume,
+                timestamp: Utc::now().timestamp(),
+            });
         }
 
         Ok(())
     }
 }
+
+// Salt and hash an auth key for storage/comparison; never log or store the
+// plaintext key itself.
+fn hash_auth_key(salt: &[u8; 16], key: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(key.as_bytes());
+    hasher.finalize().into()
+}
+
+// Constant-time byte comparison so auth-key checks don't leak timing
+// information about how close a guess was.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Minimal application/x-www-form-urlencoded encoder for private POST bodies.
+// Kraken's private-endpoint params are plain identifiers and numbers, so a
+// full percent-encoding crate isn't warranted.
+fn url_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b' ' => out.push('+'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
@@ -0,0 +1,167 @@
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use metrics::gauge;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::state::{Info, State, TickerEvent};
+
+const KRAKEN_WS_URL: &str = "wss://ws.kraken.com";
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Keeps a persistent connection to Kraken's public websocket feed alive,
+// pushing fresh ticker values into the same gauges `State::generate` writes
+// to via REST. Reconnects with exponential backoff and never returns.
+pub async fn run(state: State) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let (connected_for, result) = connect_and_stream(&state).await;
+
+        // A connection that survived past the heartbeat timeout was healthy,
+        // not failing instantly, so don't make it pay the backoff built up
+        // by earlier reconnect attempts.
+        if connected_for >= HEARTBEAT_TIMEOUT {
+            backoff = INITIAL_BACKOFF;
+        }
+
+        match result {
+            Ok(()) => log::warn!("kraken websocket stream closed, reconnecting"),
+            Err(e) => log::error!("kraken websocket error: {}, reconnecting in {:?}", e, backoff),
+        }
+
+        sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+// Runs one connection attempt and reports how long it stayed connected
+// alongside the outcome, so `run` can tell a healthy long-lived connection
+// from one that failed instantly.
+async fn connect_and_stream(state: &State) -> (Duration, crate::state::BoxResult<()>) {
+    let mut connected_at = None;
+    let result = connect_and_stream_inner(state, &mut connected_at).await;
+    let connected_for = connected_at.map_or(Duration::ZERO, |at: Instant| at.elapsed());
+    (connected_for, result)
+}
+
+async fn connect_and_stream_inner(
+    state: &State,
+    connected_at: &mut Option<Instant>,
+) -> crate::state::BoxResult<()> {
+    let wsnames = state.discover_wsnames().await?;
+    if wsnames.is_empty() {
+        log::warn!("no pairs discovered for kraken websocket subscription");
+        return Ok(());
+    }
+
+    log::info!("subscribing to {} pairs on kraken websocket feed", wsnames.len());
+    let (ws_stream, _) = connect_async(KRAKEN_WS_URL).await?;
+    *connected_at = Some(Instant::now());
+    let (mut write, mut read) = ws_stream.split();
+
+    let subscribe = serde_json::json!({
+        "event": "subscribe",
+        "pair": wsnames,
+        "subscription": { "name": "ticker" }
+    });
+    write.send(Message::Text(subscribe.to_string())).await?;
+
+    loop {
+        let message = match timeout(HEARTBEAT_TIMEOUT, read.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) => return Err("kraken websocket closed the connection".into()),
+            Err(_) => return Err("no heartbeat received from kraken within timeout".into()),
+        };
+
+        match message {
+            Message::Text(text) => handle_message(state, &text),
+            Message::Close(frame) => {
+                return Err(format!("kraken websocket sent close frame: {:?}", frame).into())
+            }
+            _ => {}
+        }
+    }
+}
+
+fn handle_message(state: &State, text: &str) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(e) => {
+            log::warn!("failed to parse kraken websocket message: {}", e);
+            return;
+        }
+    };
+
+    if value.is_object() {
+        match value.get("event").and_then(Value::as_str) {
+            Some("heartbeat") => log::trace!("kraken heartbeat"),
+            Some("systemStatus") => log::info!("kraken system status: {:?}", value.get("status")),
+            Some("subscriptionStatus") => log::info!("kraken subscription status: {:?}", value),
+            Some(other) => log::debug!("unhandled kraken control event: {}", other),
+            None => log::debug!("unhandled kraken control message: {:?}", value),
+        }
+        return;
+    }
+
+    if let Value::Array(fields) = value {
+        update_ticker(state, &fields);
+    }
+}
+
+fn update_ticker(state: &State, fields: &[Value]) {
+    let (Some(payload), Some(pair)) = (fields.get(1), fields.get(3).and_then(Value::as_str)) else {
+        return;
+    };
+
+    let info: Info = match serde_json::from_value(payload.clone()) {
+        Ok(info) => info,
+        Err(e) => {
+            log::warn!("failed to parse kraken ticker payload for {}: {}", pair, e);
+            return;
+        }
+    };
+
+    let wsname_split: Vec<&str> = pair.split('/').collect();
+    if wsname_split.len() != 2 {
+        log::warn!("unexpected pair name from kraken websocket: {}", pair);
+        return;
+    }
+
+    let (Some(rate), Some(volume), Some(avg), Some(avg_last_day), Some(trades)) = (
+        info.c.get(0).and_then(|v| v.parse::<f64>().ok()),
+        info.v.get(1).and_then(|v| v.parse::<f64>().ok()),
+        info.p.get(0).and_then(|v| v.parse::<f64>().ok()),
+        info.p.get(1).and_then(|v| v.parse::<f64>().ok()),
+        info.t.get(1).copied(),
+    ) else {
+        log::warn!("malformed kraken ticker payload for {}: {:?}", pair, info);
+        return;
+    };
+
+    let labels = [
+        ("currency", wsname_split[0].to_string()),
+        ("reference_currency", wsname_split[1].to_string()),
+        ("pair", pair.to_string()),
+    ];
+
+    gauge!("exchange_rate", rate, &labels);
+    gauge!("exchange_volume_daily", volume, &labels);
+    gauge!("exchange_rate_average", avg, &labels);
+    gauge!("exchange_rate_average_last_day", avg_last_day, &labels);
+    gauge!("exchange_trades_daily", trades as f64, &labels);
+
+    state.publish(TickerEvent {
+        pair: pair.to_string(),
+        currency: wsname_split[0].to_string(),
+        reference_currency: wsname_split[1].to_string(),
+        rate,
+        volume,
+        timestamp: Utc::now().timestamp(),
+    });
+}